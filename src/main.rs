@@ -1,15 +1,20 @@
 //! An interactive serial terminal
 
 use std::convert::TryFrom;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 use std::{io, str};
 
-use bytes::{BufMut, BytesMut};
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::sink::SinkExt;
 use futures::stream::StreamExt;
-use serialport::{FlowControl, Parity, StopBits};
+use serialport::{FlowControl, Parity, SerialPortInfo, SerialPortType, StopBits};
 use structopt::clap::AppSettings;
 use structopt::StructOpt;
 use strum::{EnumString, EnumVariantNames};
+use tokio::io::AsyncWriteExt;
+use tokio::net::UnixStream;
+use tokio::select;
 use tokio_serial::DataBits;
 use tokio_util::codec::{Decoder, Encoder};
 use tokio_util::codec::{FramedRead, FramedWrite, LinesCodec, LinesCodecError};
@@ -30,12 +35,45 @@ struct Opt {
     /// Flow control (none, software, hardware)
     #[structopt(long, default_value = "none")]
     flow_control: FlowControlOpt,
+    /// Flush the currently buffered line if no more bytes arrive within this
+    /// many milliseconds, even without a trailing newline
+    #[structopt(long)]
+    idle_flush: Option<u64>,
     /// Lists available serial ports
     #[structopt(short, long)]
     list: bool,
+    /// Echo typed lines back to the local display in addition to sending
+    /// them to the device (useful when the device itself doesn't echo)
+    #[structopt(long)]
+    local_echo: bool,
+    /// Write all received data to this file, in addition to stdout
+    #[structopt(long, parse(from_os_str))]
+    log: Option<PathBuf>,
+    /// Format used when writing to --log or --log-socket (ascii, hex)
+    #[structopt(long, default_value = "ascii")]
+    log_format: LogFormat,
+    /// Additionally mirror received data to this Unix domain socket
+    #[structopt(long, parse(from_os_str))]
+    log_socket: Option<PathBuf>,
+    /// Route typed input back to the local display instead of sending it
+    /// to the device, for exercising the terminal without hardware attached
+    #[structopt(long)]
+    loopback: bool,
+    /// How to interpret bytes received from the device (line, hex, raw)
+    #[structopt(long, default_value = "line")]
+    mode: ReadMode,
     /// Parity checking (none, odd, even)
     #[structopt(long, default_value = "none")]
     parity: ParityOpt,
+    /// Reopen the port and resume if the device disconnects
+    #[structopt(long)]
+    reconnect: bool,
+    /// Milliseconds to wait between reconnect attempts
+    #[structopt(long, default_value = "1000")]
+    reconnect_delay: u64,
+    /// Open the first USB serial port matching this VID:PID (hex, e.g. 2341:0043)
+    #[structopt(long)]
+    select: Option<String>,
     /// Stop bits (1, 2)
     #[structopt(long, default_value = "1")]
     stop_bits: usize,
@@ -83,7 +121,7 @@ impl Eol {
 }
 
 /// Flow control modes
-#[derive(Debug, EnumString, EnumVariantNames, StructOpt)]
+#[derive(Debug, Clone, Copy, EnumString, EnumVariantNames, StructOpt)]
 #[strum(serialize_all = "snake_case")]
 enum FlowControlOpt {
     /// No flow control.
@@ -104,7 +142,7 @@ impl From<FlowControlOpt> for FlowControl {
     }
 }
 
-#[derive(Debug, EnumString, EnumVariantNames, StructOpt)]
+#[derive(Debug, Clone, Copy, EnumString, EnumVariantNames, StructOpt)]
 #[strum(serialize_all = "snake_case")]
 enum ParityOpt {
     /// No parity bit.
@@ -125,6 +163,125 @@ impl From<ParityOpt> for Parity {
     }
 }
 
+/// Format used when mirroring received data to a log file or socket
+#[derive(Debug, Clone, Copy, EnumString, EnumVariantNames, StructOpt)]
+#[strum(serialize_all = "snake_case")]
+enum LogFormat {
+    /// Plain text, one line per entry.
+    Ascii,
+    /// Space-separated hex bytes, one line per entry.
+    Hex,
+}
+
+fn format_log_line(line: &str, format: LogFormat) -> String {
+    match format {
+        LogFormat::Ascii => format!("{}\n", line),
+        LogFormat::Hex => {
+            let hex = line
+                .bytes()
+                .map(|b| format!("{:02x}", b))
+                .collect::<Vec<_>>()
+                .join(" ");
+            format!("{}\n", hex)
+        }
+    }
+}
+
+/// How bytes received from the device are interpreted
+#[derive(Debug, Clone, Copy, EnumString, EnumVariantNames, StructOpt)]
+#[strum(serialize_all = "snake_case")]
+enum ReadMode {
+    /// Newline-delimited UTF-8 text.
+    Line,
+    /// Canonical hexdump of whatever bytes are currently buffered.
+    Hex,
+    /// Raw bytes, passed through untouched with no delimiter or decoding.
+    Raw,
+}
+
+fn parse_vid_pid(s: &str) -> Result<(u16, u16), String> {
+    let mut parts = s.splitn(2, ':');
+    let vid = parts.next().filter(|s| !s.is_empty());
+    let pid = parts.next().filter(|s| !s.is_empty());
+    match (vid, pid) {
+        (Some(vid), Some(pid)) => {
+            let vid = u16::from_str_radix(vid, 16).map_err(|e| e.to_string())?;
+            let pid = u16::from_str_radix(pid, 16).map_err(|e| e.to_string())?;
+            Ok((vid, pid))
+        }
+        _ => Err("expected VID:PID, e.g. 2341:0043".to_string()),
+    }
+}
+
+fn find_usb_port(ports: &[SerialPortInfo], vid: u16, pid: u16) -> Option<&SerialPortInfo> {
+    ports.iter().find(|port| {
+        matches!(&port.port_type, SerialPortType::UsbPort(info) if info.vid == vid && info.pid == pid)
+    })
+}
+
+fn describe_port(port: &SerialPortInfo) -> String {
+    match &port.port_type {
+        SerialPortType::UsbPort(info) => format!(
+            "{} ({})",
+            info.product.as_deref().unwrap_or("unknown product"),
+            info.serial_number.as_deref().unwrap_or("no serial number")
+        ),
+        _ => "non-USB port".to_string(),
+    }
+}
+
+fn prompt_port_choice(ports: &[SerialPortInfo]) -> &SerialPortInfo {
+    eprintln!("Multiple serial ports found:");
+    for (i, port) in ports.iter().enumerate() {
+        eprintln!("  [{}] {} - {}", i, port.port_name, describe_port(port));
+    }
+
+    loop {
+        eprint!("Select a port [0-{}]: ", ports.len() - 1);
+        let mut input = String::new();
+        if io::stdin().read_line(&mut input).is_err() {
+            eprintln!("Failed to read selection");
+            std::process::exit(1);
+        }
+        if let Some(port) = input.trim().parse::<usize>().ok().and_then(|i| ports.get(i)) {
+            return port;
+        }
+        eprintln!("Invalid selection, try again");
+    }
+}
+
+/// Identifies the device to wait for when reconnecting after a disconnect.
+enum PortTarget {
+    Path(PathBuf),
+    Usb(u16, u16),
+}
+
+impl PortTarget {
+    fn find(&self, ports: &[SerialPortInfo]) -> Option<PathBuf> {
+        match self {
+            Self::Path(path) => ports
+                .iter()
+                .find(|port| PathBuf::from(&port.port_name) == *path)
+                .map(|port| PathBuf::from(&port.port_name)),
+            Self::Usb(vid, pid) => {
+                find_usb_port(ports, *vid, *pid).map(|port| PathBuf::from(&port.port_name))
+            }
+        }
+    }
+}
+
+async fn wait_for_reconnect(target: &PortTarget, delay: Duration) -> PathBuf {
+    loop {
+        if let Ok(ports) = serialport::available_ports() {
+            if let Some(path) = target.find(&ports) {
+                return path;
+            }
+        }
+        eprintln!("Waiting for device to reappear...");
+        tokio::time::sleep(delay).await;
+    }
+}
+
 struct StopBitsExt(StopBits);
 
 impl TryFrom<usize> for StopBitsExt {
@@ -162,6 +319,118 @@ impl Decoder for SerialReadCodec {
     }
 }
 
+/// Batches received bytes into canonical hexdump rows.
+#[derive(Default)]
+struct HexCodec {
+    offset: usize,
+}
+
+impl HexCodec {
+    fn flush(&mut self, src: &mut BytesMut) -> Option<String> {
+        if src.is_empty() {
+            return None;
+        }
+
+        let chunk = src.split_to(src.len());
+        let dump = hexdump(&chunk, self.offset);
+        self.offset += chunk.len();
+
+        Some(dump)
+    }
+}
+
+impl Decoder for HexCodec {
+    type Item = String;
+    type Error = LinesCodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        Ok(self.flush(src))
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        Ok(self.flush(src))
+    }
+}
+
+fn hexdump(data: &[u8], base_offset: usize) -> String {
+    let mut rows = Vec::new();
+    for (i, chunk) in data.chunks(16).enumerate() {
+        let mut hex = String::new();
+        let mut ascii = String::new();
+        for (j, b) in chunk.iter().enumerate() {
+            if j == 8 {
+                hex.push(' ');
+            }
+            hex.push_str(&format!("{:02x} ", b));
+            ascii.push(if b.is_ascii_graphic() || *b == b' ' {
+                *b as char
+            } else {
+                '.'
+            });
+        }
+        rows.push(format!(
+            "{:08x}  {:<49}|{}|",
+            base_offset + i * 16,
+            hex,
+            ascii
+        ));
+    }
+    rows.join("\n")
+}
+
+/// Passes received bytes straight through, unbuffered and unmodified.
+struct RawCodec;
+
+impl Decoder for RawCodec {
+    type Item = Bytes;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if src.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(src.split_to(src.len()).freeze()))
+    }
+}
+
+/// Dispatches to the `SerialReadCodec`/`HexCodec` selected by `--mode` for
+/// the line-oriented modes. `--mode raw` bypasses this entirely in favor of
+/// `RawCodec` and `RawSink`, since it has no notion of a "line".
+enum SerialCodec {
+    Line(SerialReadCodec),
+    Hex(HexCodec),
+}
+
+impl Decoder for SerialCodec {
+    type Item = String;
+    type Error = LinesCodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        match self {
+            Self::Line(codec) => codec.decode(src),
+            Self::Hex(codec) => codec.decode(src),
+        }
+    }
+}
+
+impl SerialCodec {
+    /// Force out whatever is buffered but hasn't completed a line/row yet.
+    fn flush_pending(&mut self, src: &mut BytesMut) -> Option<String> {
+        match self {
+            Self::Line(_) => {
+                if src.is_empty() {
+                    None
+                } else {
+                    let buf = src.split_to(src.len());
+                    Some(String::from_utf8_lossy(&buf).trim().to_string())
+                }
+            }
+            Self::Hex(codec) => codec.flush(src),
+        }
+    }
+}
+
 struct SerialWriteCodec(Eol);
 
 impl Encoder<String> for SerialWriteCodec {
@@ -177,6 +446,231 @@ impl Encoder<String> for SerialWriteCodec {
     }
 }
 
+/// A destination that decoded serial output is mirrored to.
+enum SerialSink {
+    Stdout(FramedWrite<tokio::io::Stdout, LinesCodec>),
+    File(tokio::fs::File),
+    Socket(UnixStream),
+}
+
+impl SerialSink {
+    async fn write_line(&mut self, line: &str, format: LogFormat) -> io::Result<()> {
+        match self {
+            Self::Stdout(framed) => framed
+                .send(line.to_string())
+                .await
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e)),
+            Self::File(file) => file.write_all(format_log_line(line, format).as_bytes()).await,
+            Self::Socket(socket) => {
+                socket
+                    .write_all(format_log_line(line, format).as_bytes())
+                    .await
+            }
+        }
+    }
+}
+
+/// A destination for raw received bytes, written exactly as received.
+enum RawSink {
+    Stdout(tokio::io::Stdout),
+    File(tokio::fs::File),
+    Socket(UnixStream),
+}
+
+impl RawSink {
+    async fn write_bytes(&mut self, data: &[u8]) -> io::Result<()> {
+        match self {
+            Self::Stdout(stdout) => {
+                stdout.write_all(data).await?;
+                stdout.flush().await
+            }
+            Self::File(file) => file.write_all(data).await,
+            Self::Socket(socket) => socket.write_all(data).await,
+        }
+    }
+}
+
+fn io_err<E: std::fmt::Debug>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("{:?}", e))
+}
+
+async fn pump_input(
+    opt: &Opt,
+    mut framed_stdin: FramedRead<tokio::io::Stdin, LinesCodec>,
+    mut sink: FramedWrite<tokio::io::WriteHalf<tokio_serial::Serial>, SerialWriteCodec>,
+) -> io::Result<()> {
+    let mut echo = if opt.local_echo || opt.loopback {
+        Some(FramedWrite::new(tokio::io::stdout(), LinesCodec::new()))
+    } else {
+        None
+    };
+
+    while let Some(item) = framed_stdin.next().await {
+        let line = item.map_err(io_err)?;
+        if let Some(echo) = &mut echo {
+            echo.send(line.clone()).await.map_err(io_err)?;
+        }
+        if !opt.loopback {
+            sink.send(line).await.map_err(io_err)?;
+        }
+    }
+    Ok(())
+}
+
+async fn connect_and_pump(
+    opt: &Opt,
+    tty_path: &Path,
+    settings: &tokio_serial::SerialPortSettings,
+) -> io::Result<()> {
+    println!("Opening serial connection to device {:?}", tty_path);
+    let serial = tokio_serial::Serial::from_path(tty_path, settings);
+    if serial.is_err() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("Failed to create serial port: {:?}", serial.err()),
+        ));
+    }
+    #[allow(unused_mut)]
+    let mut serial = serial.unwrap();
+
+    #[cfg(unix)]
+    serial
+        .set_exclusive(false)
+        .expect("Unable to set serial port exclusive to false");
+
+    let stdin = tokio::io::stdin();
+    let framed_stdin = FramedRead::new(stdin, LinesCodec::new());
+
+    let (read, write) = tokio::io::split(serial);
+    let sink = FramedWrite::new(write, SerialWriteCodec(opt.eol));
+
+    let log_file = match &opt.log {
+        Some(log_path) => match tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(log_path)
+            .await
+        {
+            Ok(file) => Some(file),
+            Err(e) => {
+                eprintln!("Failed to open log file {:?}: {:?}", log_path, e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    let log_socket = match &opt.log_socket {
+        Some(socket_path) => match UnixStream::connect(socket_path).await {
+            Ok(socket) => Some(socket),
+            Err(e) => {
+                eprintln!("Failed to connect to log socket {:?}: {:?}", socket_path, e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    if let ReadMode::Raw = opt.mode {
+        let mut raw_sinks = vec![RawSink::Stdout(tokio::io::stdout())];
+        if let Some(file) = log_file {
+            raw_sinks.push(RawSink::File(file));
+        }
+        if let Some(socket) = log_socket {
+            raw_sinks.push(RawSink::Socket(socket));
+        }
+
+        let mut stream = FramedRead::new(read, RawCodec);
+        let output = async {
+            while let Some(item) = stream.next().await {
+                let chunk = item?;
+                for sink in &mut raw_sinks {
+                    sink.write_bytes(&chunk).await?;
+                }
+            }
+            Ok(())
+        };
+        let input = pump_input(opt, framed_stdin, sink);
+        return futures::future::try_join(input, output).await.map(|_| ());
+    }
+
+    let codec = match opt.mode {
+        ReadMode::Line => SerialCodec::Line(SerialReadCodec),
+        ReadMode::Hex => SerialCodec::Hex(HexCodec::default()),
+        ReadMode::Raw => unreachable!(),
+    };
+    let mut stream = FramedRead::new(read, codec);
+
+    let mut sinks = vec![SerialSink::Stdout(FramedWrite::new(
+        tokio::io::stdout(),
+        LinesCodec::new(),
+    ))];
+    if let Some(file) = log_file {
+        sinks.push(SerialSink::File(file));
+    }
+    if let Some(socket) = log_socket {
+        sinks.push(SerialSink::Socket(socket));
+    }
+
+    let input = pump_input(opt, framed_stdin, sink);
+    let output = async {
+        match opt.idle_flush {
+            None => {
+                while let Some(item) = stream.next().await {
+                    let line = item.map_err(io_err)?;
+                    for sink in &mut sinks {
+                        sink.write_line(&line, opt.log_format).await?;
+                    }
+                }
+            }
+            Some(idle_flush_ms) => {
+                let idle_flush = Duration::from_millis(idle_flush_ms);
+                let mut ticker = tokio::time::interval(idle_flush);
+                ticker.tick().await;
+                let mut pending_len = 0usize;
+                let mut last_activity = Instant::now();
+
+                loop {
+                    select! {
+                        item = stream.next() => {
+                            match item {
+                                Some(item) => {
+                                    let line = item.map_err(io_err)?;
+                                    pending_len = 0;
+                                    for sink in &mut sinks {
+                                        sink.write_line(&line, opt.log_format).await?;
+                                    }
+                                }
+                                None => break,
+                            }
+                        }
+                        _ = ticker.tick() => {
+                            let buf_len = stream.read_buffer().len();
+                            if buf_len == 0 {
+                                pending_len = 0;
+                            } else if buf_len != pending_len {
+                                pending_len = buf_len;
+                                last_activity = Instant::now();
+                            } else if last_activity.elapsed() >= idle_flush {
+                                let mut buf = std::mem::take(stream.read_buffer_mut());
+                                let line = stream.codec_mut().flush_pending(&mut buf);
+                                pending_len = 0;
+                                if let Some(line) = line {
+                                    for sink in &mut sinks {
+                                        sink.write_line(&line, opt.log_format).await?;
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    };
+    futures::future::try_join(input, output).await.map(|_| ())
+}
+
 #[tokio::main]
 async fn main() {
     let opt = Opt::from_args();
@@ -195,9 +689,37 @@ async fn main() {
         return;
     }
 
-    let tty_path = opt
-        .tty
-        .unwrap_or_else(|| PathBuf::from(&ports.first().unwrap().port_name));
+    let selected_usb = match &opt.select {
+        Some(select) => Some(parse_vid_pid(select).unwrap_or_else(|e| {
+            eprintln!("Invalid --select value {:?}: {}", select, e);
+            std::process::exit(1);
+        })),
+        None => None,
+    };
+
+    let mut tty_path = if let Some(tty) = &opt.tty {
+        tty.clone()
+    } else if let Some((vid, pid)) = selected_usb {
+        match find_usb_port(&ports, vid, pid) {
+            Some(port) => PathBuf::from(&port.port_name),
+            None => {
+                eprintln!("No USB serial port matching {:04x}:{:04x} found", vid, pid);
+                std::process::exit(1);
+            }
+        }
+    } else if ports.len() > 1 {
+        PathBuf::from(&prompt_port_choice(&ports).port_name)
+    } else {
+        PathBuf::from(&ports.first().unwrap().port_name)
+    };
+
+    let target = if opt.tty.is_some() {
+        PortTarget::Path(tty_path.clone())
+    } else if let Some((vid, pid)) = selected_usb {
+        PortTarget::Usb(vid, pid)
+    } else {
+        PortTarget::Path(tty_path.clone())
+    };
 
     let settings = tokio_serial::SerialPortSettings {
         baud_rate: opt.baud,
@@ -208,32 +730,21 @@ async fn main() {
         timeout: std::time::Duration::from_secs(5),
     };
 
-    println!("Opening serial connection to device {:?}", tty_path);
-    let serial = tokio_serial::Serial::from_path(tty_path, &settings);
-    if serial.is_err() {
-        eprintln!("Failed to create serial port: {:?}", serial.err());
-        std::process::exit(1);
+    let reconnect_delay = Duration::from_millis(opt.reconnect_delay);
+
+    loop {
+        let result = connect_and_pump(&opt, &tty_path, &settings).await;
+
+        match result {
+            Ok(()) => break,
+            Err(e) if opt.reconnect => {
+                eprintln!("Uh oh: {:?}, waiting to reconnect...", e);
+                tty_path = wait_for_reconnect(&target, reconnect_delay).await;
+            }
+            Err(e) => {
+                eprintln!("Uh oh: {:?}", e);
+                std::process::exit(1);
+            }
+        }
     }
-    #[allow(unused_mut)]
-    let mut serial = serial.unwrap();
-
-    #[cfg(unix)]
-    serial
-        .set_exclusive(false)
-        .expect("Unable to set serial port exclusive to false");
-
-    let stdout = tokio::io::stdout();
-    let stdin = tokio::io::stdin();
-    let framed_stdin = FramedRead::new(stdin, LinesCodec::new());
-    let framed_stdout = FramedWrite::new(stdout, LinesCodec::new());
-
-    let (read, write) = tokio::io::split(serial);
-    let stream = FramedRead::new(read, SerialReadCodec);
-    let sink = FramedWrite::new(write, SerialWriteCodec(opt.eol));
-
-    let input = framed_stdin.forward(sink);
-    let output = stream.forward(framed_stdout);
-    let result = futures::future::try_join(input, output).await;
-
-    eprintln!("Uh oh: {:?}", result);
 }